@@ -3,37 +3,82 @@
 
 extern crate futures;
 
-use std::sync::{Arc, Mutex, PoisonError};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
 use std::{error, fmt, convert};
 
+use futures::{task, Async, Poll};
 use futures::sync::mpsc;
+use futures::unsync::mpsc as unsync_mpsc;
 
 /// Unbounded broadcast sender. Multiple `futures` `UnboundedReceiver`s can be requested
 /// from it. Message type must implement `Clone`. Dropping a receiver is fine as it will
 /// be automatically pruned on the next send.
 ///
 /// # Todo
-/// 1. Add a cache to store messages when there are no receivers. Or, wrap it in a cache.
-/// 2. Add variant impl for `T: Copy`
+/// 1. Add variant impl for `T: Copy`
 #[derive(Clone)]
 pub struct UnboundedBroadcaster<T> {
-    sender: Arc<Mutex<Vec<mpsc::UnboundedSender<T>>>>,
+    state: Arc<Mutex<State<T>>>,
+}
+
+struct State<T> {
+    receivers: Vec<mpsc::UnboundedSender<T>>,
+    replay: Option<Replay<T>>,
+}
+
+struct Replay<T> {
+    capacity: usize,
+    buffer: VecDeque<T>,
+}
+
+impl<T: Clone> Replay<T> {
+    fn push(&mut self, msg: &T) {
+        // Evict after inserting, as `BoundedBroadcaster::send` does, so `capacity == 0`
+        // (a legitimate "buffer nothing" configuration) converges on an empty buffer
+        // instead of permanently retaining one leftover message.
+        self.buffer.push_back(msg.clone());
+        if self.buffer.len() > self.capacity {
+            self.buffer.pop_front();
+        }
+    }
 }
 
 impl<T: Clone> UnboundedBroadcaster<T> {
     pub fn new() -> Self {
         UnboundedBroadcaster {
-            sender: Arc::new(Mutex::new(Vec::new())),
+            state: Arc::new(Mutex::new(State {
+                receivers: Vec::new(),
+                replay: None,
+            })),
         }
     }
 
-    /// Send the message broadcasting it to all receivers. If there are no receivers, the
-    /// message is returned.    
-    pub fn send(&self, msg: T) -> Result<(), BroadcastError<T>> {
+    /// As `new` but also keeps the last `capacity` sent messages in a replay buffer, so
+    /// that a receiver requested later still sees them. See `receiver`.
+    pub fn with_replay(capacity: usize) -> Self {
+        UnboundedBroadcaster {
+            state: Arc::new(Mutex::new(State {
+                receivers: Vec::new(),
+                replay: Some(Replay {
+                    capacity,
+                    buffer: VecDeque::with_capacity(capacity),
+                }),
+            })),
+        }
+    }
+
+    /// Clone `msg` out to every live receiver (pruning dead ones) and, if a replay
+    /// buffer is configured, into the buffer too. Shared by `send` and
+    /// `send_reporting`; returns the number of receivers that accepted the clone and
+    /// whether a replay buffer is configured.
+    fn broadcast(&self, msg: &T) -> Result<(usize, bool), BroadcastError<T>> {
         let mut sent = 0;
-        let mut lock = self.sender.lock()?;
-        
-        lock.retain(|chan| {
+        let mut state = self.state.lock()?;
+
+        state.receivers.retain(|chan| {
             match chan.unbounded_send(msg.clone()) {
                 Ok(()) => {
                     sent += 1;
@@ -41,23 +86,107 @@ impl<T: Clone> UnboundedBroadcaster<T> {
                 },
                 Err(_) => false,
             }
-        });       
+        });
 
-        if sent > 0 {
+        let has_replay = state.replay.is_some();
+        if let Some(replay) = state.replay.as_mut() {
+            replay.push(msg);
+        }
+
+        Ok((sent, has_replay))
+    }
+
+    /// Send the message broadcasting it to all receivers and, if a replay buffer is
+    /// configured, into the buffer too. If there are no receivers and no replay buffer,
+    /// the message is returned.
+    pub fn send(&self, msg: T) -> Result<(), BroadcastError<T>> {
+        let (sent, has_replay) = self.broadcast(&msg)?;
+
+        if sent > 0 || has_replay {
             Ok(())
         } else {
             Err(BroadcastError::NoReceivers(msg))
         }
     }
 
-    /// Request a receiver from the broadcaster. Messages sent prior to this will be missed
-    /// but and messages sent after the call to this method will be received.
+    /// Number of currently registered receivers. A dropped receiver is still counted
+    /// until the next send notices its dead channel and prunes it.
+    pub fn receiver_count(&self) -> Result<usize, BroadcastError<T>> {
+        let state = self.state.lock()?;
+        Ok(state.receivers.len())
+    }
+
+    /// As `send`, but reports how many live receivers actually accepted the clone after
+    /// pruning dead ones, instead of collapsing every outcome into `Ok(())` or
+    /// `NoReceivers`. Lets a producer skip expensive clone work, back off, or log based
+    /// on whether anyone is actually listening.
+    pub fn send_reporting(&self, msg: T) -> Result<usize, BroadcastError<T>> {
+        let (sent, _) = self.broadcast(&msg)?;
+        Ok(sent)
+    }
+
+    /// Request a receiver from the broadcaster. If a replay buffer is configured, the
+    /// buffered messages are replayed into the new receiver before any live traffic;
+    /// otherwise messages sent prior to this call are missed, and messages sent after
+    /// the call to this method will be received.
     pub fn receiver(&self) -> Result<mpsc::UnboundedReceiver<T>, BroadcastError<T>> {
         let (tx, rx) = mpsc::unbounded();
-        let mut lock = self.sender.lock()?;
-        lock.push(tx);
+        let mut state = self.state.lock()?;
+
+        if let Some(replay) = state.replay.as_ref() {
+            for msg in replay.buffer.iter() {
+                // Freshly created receiver; this can't fail.
+                let _ = tx.unbounded_send(msg.clone());
+            }
+        }
+
+        state.receivers.push(tx);
         Ok(rx)
     }
+
+    /// Downgrade to a `WeakBroadcaster` that doesn't keep the broadcaster alive. Useful
+    /// for subsystems (caches, registries, supervisors) that want to reach the
+    /// broadcaster without being among its owners.
+    pub fn downgrade(&self) -> WeakBroadcaster<T> {
+        WeakBroadcaster {
+            state: Arc::downgrade(&self.state),
+        }
+    }
+}
+
+/// A non-owning handle to an `UnboundedBroadcaster`, obtained via
+/// `UnboundedBroadcaster::downgrade`. Does not keep the broadcaster's receivers alive;
+/// `upgrade` returns `None` once every strong `UnboundedBroadcaster` has been dropped.
+#[derive(Clone)]
+pub struct WeakBroadcaster<T> {
+    state: Weak<Mutex<State<T>>>,
+}
+
+impl<T: Clone> WeakBroadcaster<T> {
+    /// Attempt to upgrade to a strong `UnboundedBroadcaster`, returning `None` if it has
+    /// already been dropped.
+    pub fn upgrade(&self) -> Option<UnboundedBroadcaster<T>> {
+        self.state.upgrade().map(|state| UnboundedBroadcaster { state })
+    }
+}
+
+/// Lets a broadcaster be used as the target of `Stream::forward`, fanning a stream out
+/// to all receivers without a manual loop. The underlying channels are unbounded, so
+/// `poll_complete` is always immediately ready.
+impl<T: Clone> futures::Sink for UnboundedBroadcaster<T> {
+    type SinkItem = T;
+    type SinkError = BroadcastError<T>;
+
+    fn start_send(&mut self, msg: T) -> futures::StartSend<T, BroadcastError<T>> {
+        // `Sink::send` is in scope here too and, being by-value, wins method
+        // resolution over the inherent `&self` method of the same name.
+        UnboundedBroadcaster::send(self, msg)?;
+        Ok(futures::AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), BroadcastError<T>> {
+        Ok(Async::Ready(()))
+    }
 }
 
 pub enum BroadcastError<T> {
@@ -94,3 +223,362 @@ impl<T> error::Error for BroadcastError<T> {
         "Broadcast problem. Message can't be sent."
     }
 }
+
+/// Bounded broadcast sender. Retains only the most recent `capacity` messages in a
+/// shared ring buffer instead of handing each receiver its own unbounded channel. A
+/// receiver that falls more than `capacity` messages behind the tail has its oldest
+/// unread messages overwritten; the next `poll` reports this via
+/// `RecvError::Lagged(skipped_count)` and fast-forwards the receiver's cursor to the
+/// oldest still-retained message, rather than blocking `send` or growing memory.
+#[derive(Clone)]
+pub struct BoundedBroadcaster<T> {
+    ring: Arc<Mutex<Ring<T>>>,
+}
+
+struct Ring<T> {
+    capacity: usize,
+    base_seq: u64,
+    buffer: VecDeque<T>,
+    waiters: Vec<task::Task>,
+}
+
+impl<T: Clone> BoundedBroadcaster<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        BoundedBroadcaster {
+            ring: Arc::new(Mutex::new(Ring {
+                capacity,
+                base_seq: 0,
+                buffer: VecDeque::with_capacity(capacity),
+                waiters: Vec::new(),
+            })),
+        }
+    }
+
+    /// Append the message to the ring, evicting the oldest retained message once
+    /// `capacity` is exceeded, then wake every receiver parked at the tail. Never
+    /// blocks and never fails except on a poisoned lock.
+    pub fn send(&self, msg: T) -> Result<(), BoundedBroadcastError> {
+        let mut ring = self.ring.lock()?;
+        ring.buffer.push_back(msg);
+        if ring.buffer.len() > ring.capacity {
+            ring.buffer.pop_front();
+            ring.base_seq += 1;
+        }
+        for waiter in ring.waiters.drain(..) {
+            waiter.notify();
+        }
+        Ok(())
+    }
+
+    /// Request a receiver. Its cursor starts at the current tail, so (as with
+    /// `UnboundedBroadcaster::receiver` without a replay buffer) messages sent prior to
+    /// this call are missed.
+    pub fn receiver(&self) -> Result<BoundedReceiver<T>, BoundedBroadcastError> {
+        let ring = self.ring.lock()?;
+        let cursor = ring.base_seq + ring.buffer.len() as u64;
+        Ok(BoundedReceiver {
+            ring: self.ring.clone(),
+            cursor,
+        })
+    }
+}
+
+/// Handle returned by `BoundedBroadcaster::receiver`. Implements `futures::Stream`;
+/// `poll` yields `RecvError::Lagged(skipped_count)` once if the receiver fell behind
+/// before resuming with the oldest still-retained message.
+pub struct BoundedReceiver<T> {
+    ring: Arc<Mutex<Ring<T>>>,
+    cursor: u64,
+}
+
+impl<T: Clone> futures::Stream for BoundedReceiver<T> {
+    type Item = T;
+    type Error = RecvError;
+
+    fn poll(&mut self) -> Poll<Option<T>, RecvError> {
+        let mut ring = self.ring.lock()?;
+        let oldest = ring.base_seq;
+        let tail = ring.base_seq + ring.buffer.len() as u64;
+
+        if self.cursor < oldest {
+            let skipped = oldest - self.cursor;
+            self.cursor = oldest;
+            return Err(RecvError::Lagged(skipped));
+        }
+
+        if self.cursor == tail {
+            ring.waiters.push(task::current());
+            return Ok(Async::NotReady);
+        }
+
+        let msg = ring.buffer[(self.cursor - oldest) as usize].clone();
+        self.cursor += 1;
+        Ok(Async::Ready(Some(msg)))
+    }
+}
+
+pub enum BoundedBroadcastError {
+    Poisoned,
+}
+
+impl<G> convert::From<PoisonError<G>> for BoundedBroadcastError {
+    fn from(_: PoisonError<G>) -> Self {
+        BoundedBroadcastError::Poisoned
+    }
+}
+
+impl fmt::Display for BoundedBroadcastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &BoundedBroadcastError::Poisoned => "poisoned lock: another task failed".fmt(f),
+        }
+    }
+}
+
+impl fmt::Debug for BoundedBroadcastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &BoundedBroadcastError::Poisoned => "PoisonError { sender: .. }".fmt(f),
+        }
+    }
+}
+
+impl error::Error for BoundedBroadcastError {
+    fn description(&self) -> &str {
+        "Broadcast problem. Ring buffer can't be locked."
+    }
+}
+
+pub enum RecvError {
+    Lagged(u64),
+    Poisoned,
+}
+
+impl<G> convert::From<PoisonError<G>> for RecvError {
+    fn from(_: PoisonError<G>) -> Self {
+        RecvError::Poisoned
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &RecvError::Lagged(skipped) => write!(f, "receiver lagged, skipped {} messages", skipped),
+            &RecvError::Poisoned => "poisoned lock: another task failed".fmt(f),
+        }
+    }
+}
+
+impl fmt::Debug for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &RecvError::Lagged(skipped) => write!(f, "Lagged({})", skipped),
+            &RecvError::Poisoned => "PoisonError { sender: .. }".fmt(f),
+        }
+    }
+}
+
+impl error::Error for RecvError {
+    fn description(&self) -> &str {
+        "Broadcast problem. Receiver lagged or lock poisoned."
+    }
+}
+
+/// Single-threaded broadcast sender. Same `send`/`receiver` API as
+/// `UnboundedBroadcaster`, but built on `Rc<RefCell<..>>` over `futures::unsync::mpsc`
+/// channels instead of `Arc<Mutex<..>>` over the thread-safe `futures::sync::mpsc`,
+/// which avoids the atomic refcounting, locking and lock-poisoning overhead that a
+/// `!Send`, same-thread fan-out (event loops, local task sets) never needs.
+#[derive(Clone)]
+pub struct UnsyncBroadcaster<T> {
+    receivers: Rc<RefCell<Vec<unsync_mpsc::UnboundedSender<T>>>>,
+}
+
+impl<T: Clone> UnsyncBroadcaster<T> {
+    pub fn new() -> Self {
+        UnsyncBroadcaster {
+            receivers: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Send the message broadcasting it to all receivers. If there are no receivers, the
+    /// message is returned.
+    pub fn send(&self, msg: T) -> Result<(), UnsyncBroadcastError<T>> {
+        let mut sent = 0;
+        let mut receivers = self.receivers.borrow_mut();
+
+        receivers.retain(|chan| {
+            match chan.unbounded_send(msg.clone()) {
+                Ok(()) => {
+                    sent += 1;
+                    true
+                },
+                Err(_) => false,
+            }
+        });
+
+        if sent > 0 {
+            Ok(())
+        } else {
+            Err(UnsyncBroadcastError::NoReceivers(msg))
+        }
+    }
+
+    /// Request a receiver from the broadcaster. Messages sent prior to this will be
+    /// missed but messages sent after the call to this method will be received.
+    pub fn receiver(&self) -> unsync_mpsc::UnboundedReceiver<T> {
+        let (tx, rx) = unsync_mpsc::unbounded();
+        self.receivers.borrow_mut().push(tx);
+        rx
+    }
+}
+
+pub enum UnsyncBroadcastError<T> {
+    NoReceivers(T),
+}
+
+impl<T> fmt::Display for UnsyncBroadcastError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &UnsyncBroadcastError::NoReceivers(_) => "No receivers for send".fmt(f),
+        }
+    }
+}
+
+impl<T> fmt::Debug for UnsyncBroadcastError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &UnsyncBroadcastError::NoReceivers(_) => "No receivers for send".fmt(f),
+        }
+    }
+}
+
+impl<T> error::Error for UnsyncBroadcastError<T> {
+    fn description(&self) -> &str {
+        "Broadcast problem. Message can't be sent."
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+
+    #[test]
+    fn bounded_receiver_reports_lag_and_skips_overwritten_messages() {
+        let broadcaster = BoundedBroadcaster::with_capacity(2);
+        let mut rx = broadcaster.receiver().unwrap();
+
+        broadcaster.send(1).unwrap();
+        broadcaster.send(2).unwrap();
+        broadcaster.send(3).unwrap(); // overwrites 1
+
+        match rx.poll() {
+            Err(RecvError::Lagged(skipped)) => assert_eq!(skipped, 1),
+            other => panic!("expected Err(Lagged(1)), got {:?}", other),
+        }
+        assert_eq!(rx.poll().unwrap(), Async::Ready(Some(2)));
+        assert_eq!(rx.poll().unwrap(), Async::Ready(Some(3)));
+    }
+
+    #[test]
+    fn bounded_receiver_wakes_when_caught_up_receiver_gets_a_message() {
+        use std::thread;
+        use std::time::Duration;
+
+        let broadcaster = BoundedBroadcaster::with_capacity(4);
+        let rx = broadcaster.receiver().unwrap();
+
+        let sender = broadcaster.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            sender.send(42).unwrap();
+        });
+
+        // `wait_stream` parks the thread via `Stream::poll`'s NotReady/task::current()
+        // path and only returns once `send` notifies it; it would hang forever (and
+        // this test would time out) if that wakeup were never wired up.
+        let mut spawned = futures::executor::spawn(rx);
+        match spawned.wait_stream() {
+            Some(Ok(msg)) => assert_eq!(msg, 42),
+            other => panic!("expected Some(Ok(42)), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn with_replay_zero_buffers_nothing() {
+        let broadcaster = UnboundedBroadcaster::with_replay(0);
+        for i in 0..10 {
+            broadcaster.send(i).unwrap();
+        }
+
+        let rx = broadcaster.receiver().unwrap();
+        broadcaster.send(99).unwrap();
+
+        let received: Vec<_> = rx.wait().map(Result::unwrap).take(1).collect();
+        assert_eq!(received, vec![99]);
+    }
+
+    #[test]
+    fn receiver_count_and_send_reporting_reflect_pruning() {
+        let broadcaster = UnboundedBroadcaster::new();
+        assert_eq!(broadcaster.receiver_count().unwrap(), 0);
+
+        let rx1 = broadcaster.receiver().unwrap();
+        let rx2 = broadcaster.receiver().unwrap();
+        assert_eq!(broadcaster.receiver_count().unwrap(), 2);
+        assert_eq!(broadcaster.send_reporting(1).unwrap(), 2);
+
+        // Dropping a receiver doesn't prune it until the next send notices the dead
+        // channel, same as `send`'s existing retain-based pruning.
+        drop(rx1);
+        assert_eq!(broadcaster.send_reporting(2).unwrap(), 1);
+        assert_eq!(broadcaster.receiver_count().unwrap(), 1);
+
+        drop(rx2);
+    }
+
+    #[test]
+    fn weak_broadcaster_upgrades_while_alive_and_not_after_drop() {
+        let broadcaster = UnboundedBroadcaster::<i32>::new();
+        let weak = broadcaster.downgrade();
+
+        let upgraded = weak.upgrade().expect("should upgrade while a strong ref lives");
+        // The upgraded handle shares state with the original; receivers registered
+        // through either are visible via the other.
+        let _rx = upgraded.receiver().unwrap();
+        assert_eq!(broadcaster.receiver_count().unwrap(), 1);
+        drop(upgraded);
+
+        drop(broadcaster);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn sink_start_send_and_poll_complete() {
+        // Scoped to this test only: `Sink`'s by-value `send` would otherwise shadow
+        // the inherent `&self` `send` used by every other test in this module.
+        use futures::Sink;
+
+        let mut broadcaster = UnboundedBroadcaster::new();
+        let rx = broadcaster.receiver().unwrap();
+
+        assert_eq!(Sink::start_send(&mut broadcaster, 1).unwrap(), futures::AsyncSink::Ready);
+        assert_eq!(Sink::poll_complete(&mut broadcaster).unwrap(), Async::Ready(()));
+
+        let received: Vec<_> = rx.wait().map(Result::unwrap).take(1).collect();
+        assert_eq!(received, vec![1]);
+    }
+
+    #[test]
+    fn sink_forwards_a_stream_to_receivers() {
+        let broadcaster = UnboundedBroadcaster::new();
+        let rx = broadcaster.receiver().unwrap();
+
+        let stream = futures::stream::iter_ok::<_, BroadcastError<i32>>(vec![1, 2, 3]);
+        let _ = stream.forward(broadcaster).wait().unwrap();
+
+        let received: Vec<_> = rx.wait().map(Result::unwrap).take(3).collect();
+        assert_eq!(received, vec![1, 2, 3]);
+    }
+}